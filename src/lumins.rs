@@ -0,0 +1,4 @@
+pub mod archive;
+pub mod core;
+pub mod file_ops;
+pub mod parse;
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+/// A snapshot of one path as it existed after the last successful
+/// `core::sync_bidirectional` run
+///
+/// Comparing a replica's current fingerprint against its `ArchiveEntry`
+/// tells `sync_bidirectional` whether that side changed since the last run,
+/// which is what distinguishes a new file from a deleted one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub size: u64,
+    pub hash: Vec<u8>,
+    pub mtime: i64,
+}
+
+/// A persisted, path-keyed snapshot of both replicas as of the last
+/// successful bidirectional sync
+///
+/// Stored as tab-separated `path\tsize\thash\tmtime` lines so the archive
+/// file stays human-inspectable
+#[derive(Debug, Default)]
+pub struct Archive {
+    entries: HashMap<String, ArchiveEntry>,
+}
+
+impl Archive {
+    /// Loads the archive from `path`, or returns an empty archive if it
+    /// does not exist yet (i.e. this is the first run)
+    pub fn load(path: &str) -> Archive {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Archive::default(),
+        };
+
+        let mut entries = HashMap::new();
+        for line in contents.lines() {
+            if let Some(entry) = parse_line(line) {
+                entries.insert(entry.0, entry.1);
+            }
+        }
+
+        Archive { entries }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ArchiveEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn set(&mut self, path: &str, entry: ArchiveEntry) {
+        self.entries.insert(path.to_string(), entry);
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.entries.remove(path);
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    /// Persists the archive to `path`
+    ///
+    /// # Errors
+    /// This function will return an error in the following situations,
+    /// but is not limited to just these cases:
+    /// * `path` cannot be written
+    pub fn save(&self, path: &str) -> Result<(), io::Error> {
+        let mut contents = String::new();
+        for (entry_path, entry) in &self.entries {
+            contents.push_str(entry_path);
+            contents.push('\t');
+            contents.push_str(&entry.size.to_string());
+            contents.push('\t');
+            contents.push_str(&hex_encode(&entry.hash));
+            contents.push('\t');
+            contents.push_str(&entry.mtime.to_string());
+            contents.push('\n');
+        }
+
+        fs::write(path, contents)
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, ArchiveEntry)> {
+    let mut fields = line.splitn(4, '\t');
+    let path = fields.next()?;
+    let size = fields.next()?.parse().ok()?;
+    let hash = hex_decode(fields.next()?)?;
+    let mtime = fields.next()?.parse().ok()?;
+
+    Some((path.to_string(), ArchiveEntry { size, hash, mtime }))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test_archive {
+    use super::*;
+
+    #[test]
+    fn round_trips_hex() {
+        let bytes = vec![0u8, 1, 255, 16, 9];
+        assert_eq!(hex_decode(&hex_encode(&bytes)).unwrap(), bytes);
+    }
+
+    #[test]
+    fn parses_a_line() {
+        let entry = ArchiveEntry {
+            size: 42,
+            hash: vec![0xab, 0xcd],
+            mtime: 1234,
+        };
+        let line = format!("some/path\t42\t{}\t1234", hex_encode(&entry.hash));
+
+        let (path, parsed) = parse_line(&line).unwrap();
+        assert_eq!(path, "some/path");
+        assert_eq!(parsed, entry);
+    }
+}
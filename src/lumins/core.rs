@@ -1,7 +1,11 @@
+use log::error;
 use rayon::prelude::*;
+use std::collections::HashSet;
 use std::io;
+use std::path::Path;
 
-use crate::lumins::file_ops;
+use crate::lumins::archive::{Archive, ArchiveEntry};
+use crate::lumins::file_ops::{self, Syncable};
 use crate::lumins::parse;
 
 /// Synchronizes all files, directories, and symlinks in `dest` with `src`
@@ -9,21 +13,30 @@ use crate::lumins::parse;
 /// # Arguments
 /// * `src`: Source directory
 /// * `dest`: Destination directory
+/// * `matcher`: Determines which paths are skipped via `--include`/`--exclude`/`--gitignore`
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `src` is an invalid directory
 /// * `dest` is an invalid directory
-pub fn synchronize(src: &str, dest: &str, flags: u32) -> Result<(), io::Error> {
+///
+/// Individual file operation failures do not short-circuit the sync; they
+/// are instead collected into the returned `SyncSummary`
+pub fn synchronize(
+    src: &str,
+    dest: &str,
+    flags: u32,
+    matcher: &parse::Matcher,
+) -> Result<file_ops::SyncSummary, io::Error> {
     // Retrieve data from src directory about files, dirs, symlinks
-    let src_file_sets = file_ops::get_all_files(&src)?;
+    let src_file_sets = file_ops::get_all_files(&src, matcher, flags)?;
     let src_files = src_file_sets.files();
     let src_dirs = src_file_sets.dirs();
     let src_symlinks = src_file_sets.symlinks();
 
     // Retrieve data from dest directory about files, dirs, symlinks
-    let dest_file_sets = file_ops::get_all_files(&dest)?;
+    let dest_file_sets = file_ops::get_all_files(&dest, matcher, flags)?;
     let dest_files = dest_file_sets.files();
     let dest_dirs = dest_file_sets.dirs();
     let dest_symlinks = dest_file_sets.symlinks();
@@ -31,35 +44,37 @@ pub fn synchronize(src: &str, dest: &str, flags: u32) -> Result<(), io::Error> {
     // Determine whether or not to delete
     let delete = !parse::contains_flag(flags, parse::Flag::NoDelete);
 
+    let mut summary = file_ops::SyncSummary::default();
+
     // Delete files and symlinks
     if delete {
         let symlinks_to_delete = dest_symlinks.par_difference(&src_symlinks);
         let files_to_delete = dest_files.par_difference(&src_files);
 
-        file_ops::delete_files(symlinks_to_delete, &dest);
-        file_ops::delete_files(files_to_delete, &dest);
+        summary = summary.merge(file_ops::delete_files(symlinks_to_delete, &dest));
+        summary = summary.merge(file_ops::delete_files(files_to_delete, &dest));
     }
 
     let dirs_to_copy = src_dirs.par_difference(&dest_dirs);
-    file_ops::copy_files(dirs_to_copy, &src, &dest);
+    summary = summary.merge(file_ops::copy_files(dirs_to_copy, &src, &dest, flags));
 
     let symlinks_to_copy = src_symlinks.par_difference(&dest_symlinks);
-    file_ops::copy_files(symlinks_to_copy, &src, &dest);
+    summary = summary.merge(file_ops::copy_files(symlinks_to_copy, &src, &dest, flags));
 
     let files_to_copy = src_files.par_difference(&dest_files);
     let files_to_compare = src_files.par_intersection(&dest_files);
 
-    file_ops::copy_files(files_to_copy, &src, &dest);
-    file_ops::compare_and_copy_files(files_to_compare, &src, &dest, flags);
+    summary = summary.merge(file_ops::copy_files(files_to_copy, &src, &dest, flags));
+    summary = summary.merge(file_ops::compare_and_copy_files(files_to_compare, &src, &dest, flags));
 
     // Delete dirs in the correct order
     if delete {
         let dirs_to_delete = dest_dirs.par_difference(&src_dirs);
         let dirs_to_delete: Vec<&file_ops::Dir> = file_ops::sort_files(dirs_to_delete);
-        file_ops::delete_files_sequential(dirs_to_delete, &dest);
+        summary = summary.merge(file_ops::delete_files_sequential(dirs_to_delete, &dest));
     }
 
-    Ok(())
+    Ok(summary)
 }
 
 /// Copies all files, directories, and symlinks in `src` to `dest`
@@ -67,27 +82,383 @@ pub fn synchronize(src: &str, dest: &str, flags: u32) -> Result<(), io::Error> {
 /// # Arguments
 /// * `src`: Source directory
 /// * `dest`: Destination directory
+/// * `flags`: Bitfield of `parse::Flag`s controlling how files are copied
+/// * `matcher`: Determines which paths are skipped via `--include`/`--exclude`/`--gitignore`
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `src` is an invalid directory
 /// * `dest` is an invalid directory
-pub fn copy(src: &str, dest: &str) -> Result<(), io::Error> {
+///
+/// Individual file operation failures do not short-circuit the copy; they
+/// are instead collected into the returned `SyncSummary`
+pub fn copy(
+    src: &str,
+    dest: &str,
+    flags: u32,
+    matcher: &parse::Matcher,
+) -> Result<file_ops::SyncSummary, io::Error> {
     // Retrieve data from src directory about files, dirs, symlinks
-    let src_file_sets = file_ops::get_all_files(&src)?;
+    let src_file_sets = file_ops::get_all_files(&src, matcher, flags)?;
     let src_files = src_file_sets.files();
     let src_dirs = src_file_sets.dirs();
     let src_symlinks = src_file_sets.symlinks();
 
     // Copy everything
-    file_ops::copy_files(src_dirs.into_par_iter(), &src, &dest);
-    file_ops::copy_files(src_files.into_par_iter(), &src, &dest);
-    file_ops::copy_files(src_symlinks.into_par_iter(), &src, &dest);
+    let mut summary = file_ops::SyncSummary::default();
+    summary = summary.merge(file_ops::copy_files(src_dirs.into_par_iter(), &src, &dest, flags));
+    summary = summary.merge(file_ops::copy_files(src_files.into_par_iter(), &src, &dest, flags));
+    summary = summary.merge(file_ops::copy_files(src_symlinks.into_par_iter(), &src, &dest, flags));
+
+    Ok(summary)
+}
+
+/// How to resolve a path that was modified on both `src` and `dest` since
+/// the last `sync_bidirectional` run
+pub enum Prefer {
+    /// Keep the `src` version and overwrite `dest` with it
+    Src,
+    /// Keep the `dest` version and overwrite `src` with it
+    Dest,
+    /// Keep whichever version has the more recent mtime
+    Newer,
+}
+
+/// Two-way syncs `src` and `dest`, using an on-disk `archive` of the last
+/// successful run to tell a new file on one side apart from a file deleted
+/// on the other
+///
+/// For each path found in either replica (or still recorded in the
+/// archive), this compares its current Blake2 hash on each side against the
+/// archived one (size and mtime are recorded, but not compared):
+/// * changed on exactly one side: that change is propagated to the other
+/// * deleted on one side, unchanged on the other: deleted on both
+/// * modified on both sides: a conflict, resolved by `prefer` (default:
+///   skip and warn)
+///
+/// The reconciled state of every path that was actually resolved is
+/// persisted back to `archive` after a successful run. A conflict that was
+/// skipped (no `prefer` given) leaves its archive entry untouched, so it is
+/// re-detected and re-warned about on every subsequent run until the user
+/// resolves it.
+///
+/// # Arguments
+/// * `src`: First replica
+/// * `dest`: Second replica
+/// * `archive_path`: Path to the persisted archive of the last run
+/// * `flags`: Bitfield of `parse::Flag`s controlling how files are copied
+/// * `matcher`: Determines which paths are skipped via `--include`/`--exclude`/`--gitignore`
+/// * `prefer`: How to resolve a path modified on both sides; `None` skips and warns
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * `dest` is an invalid directory
+/// * `archive_path` cannot be written
+///
+/// Individual path failures (copy errors, or conflicts left unresolved
+/// because no `--prefer` was given) do not short-circuit the sync; they
+/// are instead collected into the returned `SyncSummary`, so a caller can
+/// tell a fully-reconciled run apart from one that left work undone
+pub fn sync_bidirectional(
+    src: &str,
+    dest: &str,
+    archive_path: &str,
+    flags: u32,
+    matcher: &parse::Matcher,
+    prefer: &Option<Prefer>,
+) -> Result<file_ops::SyncSummary, io::Error> {
+    let mut archive = Archive::load(archive_path);
+
+    let src_files = file_ops::get_all_files(&src, matcher, flags)?.files();
+    let dest_files = file_ops::get_all_files(&dest, matcher, flags)?.files();
+
+    let mut paths: HashSet<String> = HashSet::new();
+    paths.extend(src_files.iter().map(|f| f.path.clone()));
+    paths.extend(dest_files.iter().map(|f| f.path.clone()));
+    paths.extend(archive.paths().cloned());
+
+    let mut summary = file_ops::SyncSummary::default();
+    for path in paths {
+        summary = summary.merge(reconcile_path(&path, src, dest, &mut archive, prefer));
+    }
+
+    archive.save(archive_path)?;
+
+    Ok(summary)
+}
+
+/// Reconciles a single path between `src` and `dest` using the three-way
+/// diff described in `sync_bidirectional`, updating `archive` in place and
+/// reporting whether `path` ended up fully reconciled
+fn reconcile_path(
+    path: &str,
+    src: &str,
+    dest: &str,
+    archive: &mut Archive,
+    prefer: &Option<Prefer>,
+) -> file_ops::SyncSummary {
+    let src_path = Path::new(src).join(path);
+    let dest_path = Path::new(dest).join(path);
+
+    let src_fp = file_ops::fingerprint(&src_path);
+    let dest_fp = file_ops::fingerprint(&dest_path);
+    let archived = archive.get(path).cloned();
+
+    let src_changed = match (&src_fp, &archived) {
+        (Some((_, hash, _)), Some(a)) => hash != &a.hash,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    let dest_changed = match (&dest_fp, &archived) {
+        (Some((_, hash, _)), Some(a)) => hash != &a.hash,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+
+    match (&src_fp, &dest_fp) {
+        (Some(_), None) if archived.is_some() && !src_changed => {
+            // Deleted on dest, unchanged on src: propagate the deletion
+            let _ = std::fs::remove_file(&src_path);
+            archive.remove(path);
+            file_ops::SyncSummary::success(path)
+        }
+        (None, Some(_)) if archived.is_some() && !dest_changed => {
+            // Deleted on src, unchanged on dest: propagate the deletion
+            let _ = std::fs::remove_file(&dest_path);
+            archive.remove(path);
+            file_ops::SyncSummary::success(path)
+        }
+        (Some((_, _, src_mtime)), None) if archived.is_some() && src_changed => {
+            // Modified on src, deleted on dest: a conflict between an edit
+            // and a deletion
+            let archived_mtime = archived.as_ref().unwrap().mtime;
+            match resolve_delete_conflict(path, &src_path, &dest_path, true, *src_mtime, archived_mtime, prefer) {
+                Ok(DeleteResolution::Kept) => {
+                    record(archive, path, &src_path, &dest_path);
+                    file_ops::SyncSummary::success(path)
+                }
+                Ok(DeleteResolution::Deleted) => {
+                    archive.remove(path);
+                    file_ops::SyncSummary::success(path)
+                }
+                Ok(DeleteResolution::Skipped) => file_ops::SyncSummary::failure(path, conflict_skipped_error()),
+                Err(e) => {
+                    error!("Conflict Error: {}: {}", path, e);
+                    file_ops::SyncSummary::failure(path, e)
+                }
+            }
+        }
+        (None, Some((_, _, dest_mtime))) if archived.is_some() && dest_changed => {
+            // Modified on dest, deleted on src: a conflict between an edit
+            // and a deletion
+            let archived_mtime = archived.as_ref().unwrap().mtime;
+            match resolve_delete_conflict(path, &src_path, &dest_path, false, *dest_mtime, archived_mtime, prefer) {
+                Ok(DeleteResolution::Kept) => {
+                    record(archive, path, &src_path, &dest_path);
+                    file_ops::SyncSummary::success(path)
+                }
+                Ok(DeleteResolution::Deleted) => {
+                    archive.remove(path);
+                    file_ops::SyncSummary::success(path)
+                }
+                Ok(DeleteResolution::Skipped) => file_ops::SyncSummary::failure(path, conflict_skipped_error()),
+                Err(e) => {
+                    error!("Conflict Error: {}: {}", path, e);
+                    file_ops::SyncSummary::failure(path, e)
+                }
+            }
+        }
+        (Some(_), None) | (None, Some(_)) if archived.is_none() => {
+            // Brand new on exactly one side: copy it to the other
+            let (from, to) = if src_fp.is_some() {
+                (&src_path, &dest_path)
+            } else {
+                (&dest_path, &src_path)
+            };
+            if let Err(e) = copy_reconciled(from, to) {
+                error!("Sync Error: {} -> {}: {}", from.display(), to.display(), e);
+                return file_ops::SyncSummary::failure(path, e);
+            }
+            record(archive, path, &src_path, &dest_path);
+            file_ops::SyncSummary::success(path)
+        }
+        (Some(_), Some(_)) if src_changed && !dest_changed => {
+            if let Err(e) = copy_reconciled(&src_path, &dest_path) {
+                error!("Sync Error: {} -> {}: {}", src_path.display(), dest_path.display(), e);
+                return file_ops::SyncSummary::failure(path, e);
+            }
+            record(archive, path, &src_path, &dest_path);
+            file_ops::SyncSummary::success(path)
+        }
+        (Some(_), Some(_)) if dest_changed && !src_changed => {
+            if let Err(e) = copy_reconciled(&dest_path, &src_path) {
+                error!("Sync Error: {} -> {}: {}", dest_path.display(), src_path.display(), e);
+                return file_ops::SyncSummary::failure(path, e);
+            }
+            record(archive, path, &src_path, &dest_path);
+            file_ops::SyncSummary::success(path)
+        }
+        (Some((_, src_hash, _)), Some((_, dest_hash, _))) if src_changed && dest_changed => {
+            if src_hash == dest_hash {
+                // Converged on the same content independently
+                record(archive, path, &src_path, &dest_path);
+                return file_ops::SyncSummary::success(path);
+            }
+
+            match resolve_conflict(path, &src_path, &dest_path, prefer) {
+                Ok(Resolution::Resolved) => {
+                    record(archive, path, &src_path, &dest_path);
+                    file_ops::SyncSummary::success(path)
+                }
+                Ok(Resolution::Skipped) => file_ops::SyncSummary::failure(path, conflict_skipped_error()),
+                Err(e) => {
+                    error!("Conflict Error: {}: {}", path, e);
+                    file_ops::SyncSummary::failure(path, e)
+                }
+            }
+        }
+        (None, None) => {
+            // Deleted on both sides already
+            archive.remove(path);
+            file_ops::SyncSummary::success(path)
+        }
+        _ => {
+            // Unchanged on both sides
+            file_ops::SyncSummary::success(path)
+        }
+    }
+}
+
+/// Builds the `io::Error` recorded in a `SyncSummary` for a conflict that
+/// was left unresolved because no `--prefer` was given, so callers can
+/// tell a reconciled run apart from one that still has work for the user
+fn conflict_skipped_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "conflict skipped: no --prefer given")
+}
+
+/// Whether a both-modified conflict was actually resolved, or left alone
+///
+/// A `Skipped` conflict must not be recorded to `archive`: the archive
+/// entry has to stay exactly as it was so the conflict is re-detected (and
+/// re-warned about) on every subsequent run until the user resolves it
+enum Resolution {
+    Skipped,
+    Resolved,
+}
+
+/// Resolves a path modified on both sides, per `prefer`
+///
+/// With no preference given, the conflict is skipped and a warning is
+/// printed, leaving both replicas untouched
+fn resolve_conflict(
+    path: &str,
+    src_path: &Path,
+    dest_path: &Path,
+    prefer: &Option<Prefer>,
+) -> Result<Resolution, io::Error> {
+    match prefer {
+        Some(Prefer::Src) => copy_reconciled(src_path, dest_path).map(|()| Resolution::Resolved),
+        Some(Prefer::Dest) => copy_reconciled(dest_path, src_path).map(|()| Resolution::Resolved),
+        Some(Prefer::Newer) => {
+            let src_mtime = std::fs::metadata(src_path).and_then(|m| m.modified()).ok();
+            let dest_mtime = std::fs::metadata(dest_path).and_then(|m| m.modified()).ok();
+
+            let resolved = if src_mtime >= dest_mtime {
+                copy_reconciled(src_path, dest_path)
+            } else {
+                copy_reconciled(dest_path, src_path)
+            };
+            resolved.map(|()| Resolution::Resolved)
+        }
+        None => {
+            eprintln!("Conflict: {} was modified on both sides, skipping", path);
+            Ok(Resolution::Skipped)
+        }
+    }
+}
+
+/// Whether an edit-vs-delete conflict was kept, propagated, or left alone
+///
+/// A `Skipped` conflict must not touch `archive` at all: the entry has to
+/// stay exactly as it was so the conflict is re-detected (and re-warned
+/// about) on every subsequent run until the user resolves it
+enum DeleteResolution {
+    Skipped,
+    /// The edit was kept and copied over the missing side; both replicas
+    /// now hold it, so its fresh fingerprint should be recorded
+    Kept,
+    /// The edit was discarded and the deletion propagated; the path is
+    /// gone from both replicas, so its archive entry should be removed
+    Deleted,
+}
+
+/// Resolves a path that was edited on one side while deleted on the other
+///
+/// `edited_is_src` says which side holds the edit. With no preference
+/// given, the conflict is skipped and a warning is printed, leaving the
+/// edit in place and the deletion un-propagated
+///
+/// There is no timestamp for the deletion itself to compare against, so
+/// `Prefer::Newer` instead compares `edited_mtime` against `archived_mtime`
+/// (the mtime recorded the last time both sides matched): if the edit
+/// happened after that last-synced snapshot, it is treated as the newer
+/// change and kept; otherwise the deletion is treated as newer and wins
+fn resolve_delete_conflict(
+    path: &str,
+    src_path: &Path,
+    dest_path: &Path,
+    edited_is_src: bool,
+    edited_mtime: i64,
+    archived_mtime: i64,
+    prefer: &Option<Prefer>,
+) -> Result<DeleteResolution, io::Error> {
+    let (edited, missing, edited_is_preferred) = if edited_is_src {
+        (src_path, dest_path, matches!(prefer, Some(Prefer::Src)))
+    } else {
+        (dest_path, src_path, matches!(prefer, Some(Prefer::Dest)))
+    };
+
+    match prefer {
+        Some(Prefer::Newer) => {
+            if edited_mtime > archived_mtime {
+                copy_reconciled(edited, missing).map(|()| DeleteResolution::Kept)
+            } else {
+                std::fs::remove_file(edited).map(|()| DeleteResolution::Deleted)
+            }
+        }
+        Some(_) if edited_is_preferred => copy_reconciled(edited, missing).map(|()| DeleteResolution::Kept),
+        Some(_) => std::fs::remove_file(edited).map(|()| DeleteResolution::Deleted),
+        None => {
+            eprintln!(
+                "Conflict: {} was edited on one side and deleted on the other, skipping",
+                path
+            );
+            Ok(DeleteResolution::Skipped)
+        }
+    }
+}
 
+fn copy_reconciled(from: &Path, to: &Path) -> Result<(), io::Error> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(from, to)?;
     Ok(())
 }
 
+/// Records the reconciled state of `path` in `archive`, using whichever
+/// side now holds the file (both sides are identical once reconciled)
+fn record(archive: &mut Archive, path: &str, src_path: &Path, dest_path: &Path) {
+    if let Some((size, hash, mtime)) = file_ops::fingerprint(src_path) {
+        archive.set(path, ArchiveEntry { size, hash, mtime });
+    } else if let Some((size, hash, mtime)) = file_ops::fingerprint(dest_path) {
+        archive.set(path, ArchiveEntry { size, hash, mtime });
+    }
+}
+
 #[cfg(test)]
 mod test_synchronize {
     use super::*;
@@ -96,12 +467,12 @@ mod test_synchronize {
 
     #[test]
     fn invalid_src() {
-        assert_eq!(synchronize("/?", "src").is_err(), true);
+        assert_eq!(synchronize("/?", "src", 0, &parse::Matcher::default()).is_err(), true);
     }
 
     #[test]
     fn invalid_dest() {
-        assert_eq!(synchronize("src", "/?").is_err(), true);
+        assert_eq!(synchronize("src", "/?", 0, &parse::Matcher::default()).is_err(), true);
     }
 
     #[cfg(target_family = "unix")]
@@ -110,7 +481,7 @@ mod test_synchronize {
         const TEST_DIR: &str = "test_synchronize_dir1";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        assert_eq!(synchronize("src", TEST_DIR).is_ok(), true);
+        assert_eq!(synchronize("src", TEST_DIR, 0, &parse::Matcher::default()).is_ok(), true);
 
         let diff = Command::new("diff")
             .args(&["-r", "src", TEST_DIR])
@@ -128,7 +499,7 @@ mod test_synchronize {
         const TEST_DIR: &str = "test_synchronize_dir2";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        assert_eq!(synchronize("target/debug", TEST_DIR).is_ok(), true);
+        assert_eq!(synchronize("target/debug", TEST_DIR, 0, &parse::Matcher::default()).is_ok(), true);
 
         let diff = Command::new("diff")
             .args(&["-r", "target/debug", TEST_DIR])
@@ -147,7 +518,7 @@ mod test_synchronize {
 
         assert_eq!(diff.status.success(), false);
 
-        assert_eq!(synchronize("target/debug", TEST_DIR).is_ok(), true);
+        assert_eq!(synchronize("target/debug", TEST_DIR, 0, &parse::Matcher::default()).is_ok(), true);
 
         let diff = Command::new("diff")
             .args(&["-r", "target/debug", TEST_DIR])
@@ -179,7 +550,7 @@ mod test_synchronize {
 
         assert_eq!(diff.status.success(), false);
 
-        assert_eq!(synchronize(TEST_SRC, TEST_DEST).is_ok(), true);
+        assert_eq!(synchronize(TEST_SRC, TEST_DEST, 0, &parse::Matcher::default()).is_ok(), true);
 
         let diff = Command::new("diff")
             .args(&["-r", TEST_SRC, TEST_DEST])
@@ -192,3 +563,312 @@ mod test_synchronize {
         fs::remove_dir_all(TEST_SRC).unwrap();
     }
 }
+
+#[cfg(test)]
+mod test_sync_bidirectional {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn skipped_conflict_is_redetected_on_every_run_until_resolved() {
+        const TEST_SRC: &str = "test_sync_bidirectional_skip_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_skip_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_skip.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        // First run: brand new on both sides with the same content, so it
+        // is recorded as in sync with no conflict
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        // Modify both sides differently: a conflict with no --prefer given
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"src edit").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"dest edit").unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+            // The conflict must still be skipped on every run: neither
+            // side is overwritten, and the archive must not have been
+            // stamped with either side's hash
+            assert_eq!(fs::read([TEST_SRC, "file.txt"].join("/")).unwrap(), b"src edit");
+            assert_eq!(fs::read([TEST_DEST, "file.txt"].join("/")).unwrap(), b"dest edit");
+        }
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn new_file_propagates_to_other_side() {
+        const TEST_SRC: &str = "test_sync_bidirectional_new_file_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_new_file_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_new_file.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "only_on_src.txt"].join("/"), b"from src").unwrap();
+        fs::write([TEST_DEST, "only_on_dest.txt"].join("/"), b"from dest").unwrap();
+
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        assert_eq!(fs::read([TEST_DEST, "only_on_src.txt"].join("/")).unwrap(), b"from src");
+        assert_eq!(fs::read([TEST_SRC, "only_on_dest.txt"].join("/")).unwrap(), b"from dest");
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn deletion_propagates_to_other_side() {
+        const TEST_SRC: &str = "test_sync_bidirectional_delete_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_delete_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_delete.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "deleted_on_dest.txt"].join("/"), b"content").unwrap();
+        fs::write([TEST_DEST, "deleted_on_dest.txt"].join("/"), b"content").unwrap();
+        fs::write([TEST_SRC, "deleted_on_src.txt"].join("/"), b"content").unwrap();
+        fs::write([TEST_DEST, "deleted_on_src.txt"].join("/"), b"content").unwrap();
+
+        // First run: records both paths as in sync, with nothing to do
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        fs::remove_file([TEST_DEST, "deleted_on_dest.txt"].join("/")).unwrap();
+        fs::remove_file([TEST_SRC, "deleted_on_src.txt"].join("/")).unwrap();
+
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        assert_eq!(Path::new(TEST_SRC).join("deleted_on_dest.txt").exists(), false);
+        assert_eq!(Path::new(TEST_DEST).join("deleted_on_src.txt").exists(), false);
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn edit_delete_conflict_prefer_keeps_matching_edit() {
+        const TEST_SRC: &str = "test_sync_bidirectional_edit_delete_keep_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_edit_delete_keep_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_edit_delete_keep.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        // Edited on src, deleted on dest
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"edited").unwrap();
+        fs::remove_file([TEST_DEST, "file.txt"].join("/")).unwrap();
+
+        // --prefer src matches the side holding the edit: it should be
+        // kept and copied back over the deletion
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Src)).is_ok(),
+            true
+        );
+
+        assert_eq!(fs::read([TEST_SRC, "file.txt"].join("/")).unwrap(), b"edited");
+        assert_eq!(fs::read([TEST_DEST, "file.txt"].join("/")).unwrap(), b"edited");
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn edit_delete_conflict_prefer_propagates_deletion() {
+        const TEST_SRC: &str = "test_sync_bidirectional_edit_delete_propagate_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_edit_delete_propagate_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_edit_delete_propagate.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        // Edited on src, deleted on dest
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"edited").unwrap();
+        fs::remove_file([TEST_DEST, "file.txt"].join("/")).unwrap();
+
+        // --prefer dest does not match the side holding the edit: the
+        // deletion wins and is propagated, discarding the edit
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Dest)).is_ok(),
+            true
+        );
+
+        assert_eq!(Path::new(TEST_SRC).join("file.txt").exists(), false);
+        assert_eq!(Path::new(TEST_DEST).join("file.txt").exists(), false);
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn edit_delete_conflict_prefer_newer_keeps_edit_after_archived_snapshot() {
+        const TEST_SRC: &str = "test_sync_bidirectional_edit_delete_newer_keep_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_edit_delete_newer_keep_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_edit_delete_newer_keep.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        // Edited on src well after the archived snapshot, deleted on dest
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"edited").unwrap();
+        let far_future = filetime::FileTime::from_unix_time(32_503_680_000, 0);
+        filetime::set_file_mtime([TEST_SRC, "file.txt"].join("/"), far_future).unwrap();
+        fs::remove_file([TEST_DEST, "file.txt"].join("/")).unwrap();
+
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Newer)).is_ok(),
+            true
+        );
+
+        assert_eq!(fs::read([TEST_SRC, "file.txt"].join("/")).unwrap(), b"edited");
+        assert_eq!(fs::read([TEST_DEST, "file.txt"].join("/")).unwrap(), b"edited");
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn edit_delete_conflict_prefer_newer_propagates_deletion_over_stale_edit() {
+        const TEST_SRC: &str = "test_sync_bidirectional_edit_delete_newer_drop_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_edit_delete_newer_drop_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_edit_delete_newer_drop.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        // Edited on src, but stamped with an mtime from before the
+        // archived snapshot, deleted on dest
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"edited").unwrap();
+        let distant_past = filetime::FileTime::from_unix_time(0, 0);
+        filetime::set_file_mtime([TEST_SRC, "file.txt"].join("/"), distant_past).unwrap();
+        fs::remove_file([TEST_DEST, "file.txt"].join("/")).unwrap();
+
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Newer)).is_ok(),
+            true
+        );
+
+        assert_eq!(Path::new(TEST_SRC).join("file.txt").exists(), false);
+        assert_eq!(Path::new(TEST_DEST).join("file.txt").exists(), false);
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn both_modified_conflict_respects_prefer_src_and_dest() {
+        const TEST_SRC: &str = "test_sync_bidirectional_both_modified_src_dest_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_both_modified_src_dest_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_both_modified_src_dest.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "a.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "a.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_SRC, "b.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "b.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        fs::write([TEST_SRC, "a.txt"].join("/"), b"src edit").unwrap();
+        fs::write([TEST_DEST, "a.txt"].join("/"), b"dest edit").unwrap();
+        fs::write([TEST_SRC, "b.txt"].join("/"), b"src edit").unwrap();
+        fs::write([TEST_DEST, "b.txt"].join("/"), b"dest edit").unwrap();
+
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Src)).is_ok(),
+            true
+        );
+
+        // --prefer src overwrites both replicas with the src version
+        assert_eq!(fs::read([TEST_SRC, "a.txt"].join("/")).unwrap(), b"src edit");
+        assert_eq!(fs::read([TEST_DEST, "a.txt"].join("/")).unwrap(), b"src edit");
+
+        fs::write([TEST_SRC, "b.txt"].join("/"), b"src edit again").unwrap();
+        fs::write([TEST_DEST, "b.txt"].join("/"), b"dest edit again").unwrap();
+
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Dest)).is_ok(),
+            true
+        );
+
+        // --prefer dest overwrites both replicas with the dest version
+        assert_eq!(fs::read([TEST_SRC, "b.txt"].join("/")).unwrap(), b"dest edit again");
+        assert_eq!(fs::read([TEST_DEST, "b.txt"].join("/")).unwrap(), b"dest edit again");
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+
+    #[test]
+    fn both_modified_conflict_prefer_newer_keeps_more_recent_side() {
+        const TEST_SRC: &str = "test_sync_bidirectional_both_modified_newer_src";
+        const TEST_DEST: &str = "test_sync_bidirectional_both_modified_newer_dest";
+        const TEST_ARCHIVE: &str = "test_sync_bidirectional_both_modified_newer.archive";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let matcher = parse::Matcher::default();
+
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"original").unwrap();
+        assert_eq!(sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &None).is_ok(), true);
+
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"src edit").unwrap();
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"dest edit").unwrap();
+
+        // Stamp dest with a clearly more recent mtime than src
+        let far_future = filetime::FileTime::from_unix_time(32_503_680_000, 0);
+        filetime::set_file_mtime([TEST_DEST, "file.txt"].join("/"), far_future).unwrap();
+
+        assert_eq!(
+            sync_bidirectional(TEST_SRC, TEST_DEST, TEST_ARCHIVE, 0, &matcher, &Some(Prefer::Newer)).is_ok(),
+            true
+        );
+
+        assert_eq!(fs::read([TEST_SRC, "file.txt"].join("/")).unwrap(), b"dest edit");
+        assert_eq!(fs::read([TEST_DEST, "file.txt"].join("/")).unwrap(), b"dest edit");
+
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_SRC).unwrap();
+    }
+}
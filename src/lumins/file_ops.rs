@@ -0,0 +1,1271 @@
+use blake2::{Blake2s, Digest};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::error;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::lumins::parse::{self, Flag};
+
+/// Size, in bytes, of the blocks used for rolling-checksum delta copies
+const DELTA_BLOCK_SIZE: usize = 4096;
+
+/// Modulus used by the Adler-style weak rolling checksum
+const ADLER_MOD: u32 = 65521;
+
+/// A trait for on-disk entries (files, dirs, symlinks) that are identified
+/// by a path relative to the root of the tree being synced
+pub trait Entry {
+    fn path(&self) -> &str;
+}
+
+impl<T: Entry> Entry for &T {
+    fn path(&self) -> &str {
+        (*self).path()
+    }
+}
+
+/// The outcome of a batch of file operations (copies or deletes): every
+/// path that succeeded, and every path that failed along with its error
+///
+/// Collected across rayon's parallel iterators so a failure on one file
+/// doesn't stop the rest, and callers can still tell a fully successful
+/// run apart from a partial failure
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<(String, io::Error)>,
+}
+
+impl SyncSummary {
+    pub(crate) fn success(path: &str) -> SyncSummary {
+        SyncSummary {
+            succeeded: vec![path.to_string()],
+            failed: Vec::new(),
+        }
+    }
+
+    pub(crate) fn failure(path: &str, error: io::Error) -> SyncSummary {
+        SyncSummary {
+            succeeded: Vec::new(),
+            failed: vec![(path.to_string(), error)],
+        }
+    }
+
+    /// Merges another summary's results into this one
+    pub fn merge(mut self, mut other: SyncSummary) -> SyncSummary {
+        self.succeeded.append(&mut other.succeeded);
+        self.failed.append(&mut other.failed);
+        self
+    }
+
+    /// Whether every operation in this summary succeeded
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Mode bits, ownership, timestamp, and extended attributes captured for a
+/// `File` when `Flag::Archive` is set
+///
+/// `mtime` is truncated to whole seconds so that filesystems with coarser
+/// timestamp resolution don't register a spurious difference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub mtime: i64,
+    #[cfg(target_family = "unix")]
+    pub uid: u32,
+    #[cfg(target_family = "unix")]
+    pub gid: u32,
+    #[cfg(target_family = "unix")]
+    pub xattrs: std::collections::BTreeMap<String, Vec<u8>>,
+}
+
+impl FileMetadata {
+    fn capture(path: &Path) -> Option<FileMetadata> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        Some(FileMetadata {
+            mode: unix_mode(&metadata),
+            mtime: filetime::FileTime::from_last_modification_time(&metadata).seconds(),
+            #[cfg(target_family = "unix")]
+            uid: unix_uid(&metadata),
+            #[cfg(target_family = "unix")]
+            gid: unix_gid(&metadata),
+            #[cfg(target_family = "unix")]
+            xattrs: read_xattrs(path),
+        })
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn unix_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(target_family = "unix"))]
+fn unix_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn unix_uid(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.uid()
+}
+
+#[cfg(target_family = "unix")]
+fn unix_gid(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.gid()
+}
+
+#[cfg(target_family = "unix")]
+fn read_xattrs(path: &Path) -> std::collections::BTreeMap<String, Vec<u8>> {
+    let mut xattrs = std::collections::BTreeMap::new();
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => return xattrs,
+    };
+
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(path, &name) {
+            xattrs.insert(name.to_string_lossy().into_owned(), value);
+        }
+    }
+
+    xattrs
+}
+
+/// A regular file discovered while walking a directory tree
+#[derive(Debug, Clone)]
+pub struct File {
+    pub path: String,
+    pub size: u64,
+    pub metadata: Option<FileMetadata>,
+}
+
+impl File {
+    pub fn new(path: &str, size: u64, metadata: Option<FileMetadata>) -> File {
+        File {
+            path: path.to_string(),
+            size,
+            metadata,
+        }
+    }
+}
+
+impl Entry for File {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl PartialEq for File {
+    /// Compares only by `path`, not `metadata`
+    ///
+    /// This is what determines `HashSet` membership for the `par_difference`
+    /// / `par_intersection` calls in `core::synchronize`: a path present on
+    /// both sides but with differing metadata must land in the
+    /// intersection (`files_to_compare`), not the difference, so
+    /// `compare_and_copy_files` gets a chance to apply the cheap
+    /// metadata-only update instead of `core::synchronize` deleting and
+    /// fully rewriting the file
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+impl Eq for File {}
+impl Hash for File {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
+/// A directory discovered while walking a directory tree
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dir {
+    pub path: String,
+}
+
+impl Dir {
+    pub fn new(path: &str) -> Dir {
+        Dir {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl Entry for Dir {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Hash for Dir {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
+/// A symlink discovered while walking a directory tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symlink {
+    pub path: String,
+}
+
+impl Symlink {
+    pub fn new(path: &str) -> Symlink {
+        Symlink {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl Entry for Symlink {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Hash for Symlink {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
+/// The files, dirs, and symlinks found while walking a directory tree
+pub struct FileSets {
+    files: HashSet<File>,
+    dirs: HashSet<Dir>,
+    symlinks: HashSet<Symlink>,
+}
+
+impl FileSets {
+    pub fn files(&self) -> HashSet<File> {
+        self.files.clone()
+    }
+
+    pub fn dirs(&self) -> HashSet<Dir> {
+        self.dirs.clone()
+    }
+
+    pub fn symlinks(&self) -> HashSet<Symlink> {
+        self.symlinks.clone()
+    }
+}
+
+/// Recursively walks `root`, returning every file, dir, and symlink found,
+/// with paths relative to `root`
+///
+/// Paths rejected by `matcher` (via `--include`/`--exclude`/`--gitignore`)
+/// are left out entirely: a directory is skipped (and not descended into)
+/// only if `--exclude`/`--gitignore` reject it outright, since a directory's
+/// own path essentially never matches a file-shaped `--include` glob; a
+/// rejected file or symlink is simply never added to the returned sets
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `root` is an invalid directory
+pub fn get_all_files(root: &str, matcher: &parse::Matcher, flags: u32) -> Result<FileSets, io::Error> {
+    let mut files = HashSet::new();
+    let mut dirs = HashSet::new();
+    let mut symlinks = HashSet::new();
+    let gitignore_patterns = Vec::new();
+
+    walk(
+        Path::new(root),
+        Path::new(root),
+        &mut files,
+        &mut dirs,
+        &mut symlinks,
+        matcher,
+        &gitignore_patterns,
+        flags,
+    )?;
+
+    Ok(FileSets {
+        files,
+        dirs,
+        symlinks,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn walk(
+    root: &Path,
+    dir: &Path,
+    files: &mut HashSet<File>,
+    dirs: &mut HashSet<Dir>,
+    symlinks: &mut HashSet<Symlink>,
+    matcher: &parse::Matcher,
+    gitignore_patterns: &[String],
+    flags: u32,
+) -> Result<(), io::Error> {
+    let gitignore_patterns = if matcher.gitignore {
+        load_gitignore_patterns(dir, root, gitignore_patterns)
+    } else {
+        gitignore_patterns.to_vec()
+    };
+    let gitignore = compile_gitignore(&gitignore_patterns);
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative_path = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+
+        if matcher.is_excluded(&relative_path) {
+            continue;
+        }
+        if let Some(gitignore) = &gitignore {
+            if gitignore.is_match(&relative_path) {
+                continue;
+            }
+        }
+
+        let metadata = fs::symlink_metadata(&path)?;
+
+        if metadata.file_type().is_symlink() {
+            if matcher.is_included(&relative_path) {
+                symlinks.insert(Symlink::new(&relative_path));
+            }
+        } else if metadata.is_dir() {
+            dirs.insert(Dir::new(&relative_path));
+            walk(root, &path, files, dirs, symlinks, matcher, &gitignore_patterns, flags)?;
+        } else if matcher.is_included(&relative_path) {
+            let file_metadata = if parse::contains_flag(flags, Flag::Archive) {
+                FileMetadata::capture(&path)
+            } else {
+                None
+            };
+            files.insert(File::new(&relative_path, metadata.len(), file_metadata));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads `dir`'s own `.gitignore` (if any) and appends its patterns,
+/// anchored to `dir`'s location relative to `root`, onto `inherited`
+fn load_gitignore_patterns(dir: &Path, root: &Path, inherited: &[String]) -> Vec<String> {
+    let mut patterns = inherited.to_vec();
+
+    let contents = match fs::read_to_string(dir.join(".gitignore")) {
+        Ok(contents) => contents,
+        Err(_) => return patterns,
+    };
+
+    let prefix = dir
+        .strip_prefix(root)
+        .unwrap_or(dir)
+        .to_string_lossy()
+        .into_owned();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+
+        let pattern = line.trim_start_matches('/').trim_end_matches('/');
+        if prefix.is_empty() {
+            patterns.push(pattern.to_string());
+            patterns.push(format!("**/{}", pattern));
+        } else {
+            patterns.push(format!("{}/{}", prefix, pattern));
+            patterns.push(format!("{}/**/{}", prefix, pattern));
+        }
+    }
+
+    patterns
+}
+
+fn compile_gitignore(patterns: &[String]) -> Option<GlobSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        if let Ok(glob) = Glob::new(pattern) {
+            builder.add(glob);
+        }
+    }
+    builder.build().ok()
+}
+
+#[cfg(test)]
+mod test_file_equality {
+    use super::*;
+
+    #[test]
+    fn ignores_metadata_differences() {
+        let metadata_a = FileMetadata {
+            mode: 0o644,
+            mtime: 1,
+            #[cfg(target_family = "unix")]
+            uid: 0,
+            #[cfg(target_family = "unix")]
+            gid: 0,
+            #[cfg(target_family = "unix")]
+            xattrs: Default::default(),
+        };
+        let mut metadata_b = metadata_a.clone();
+        metadata_b.mode = 0o600;
+
+        let a = File::new("same/path", 10, Some(metadata_a));
+        let b = File::new("same/path", 10, Some(metadata_b));
+
+        assert_eq!(a, b);
+
+        let mut set = HashSet::new();
+        set.insert(a);
+        assert_eq!(set.contains(&b), true);
+    }
+
+    #[test]
+    fn differs_by_path() {
+        let a = File::new("a.txt", 10, None);
+        let b = File::new("b.txt", 10, None);
+        assert_ne!(a, b);
+    }
+}
+
+#[cfg(test)]
+mod test_get_all_files {
+    use super::*;
+    use crate::lumins::parse::Matcher;
+
+    #[test]
+    fn include_glob_reaches_nested_files() {
+        const TEST_DIR: &str = "test_get_all_files_include_nested";
+        fs::create_dir_all(Path::new(TEST_DIR).join("sub")).unwrap();
+        fs::write(Path::new(TEST_DIR).join("root.txt"), b"root").unwrap();
+        fs::write(Path::new(TEST_DIR).join("sub").join("nested.txt"), b"nested").unwrap();
+
+        let matcher = Matcher::new(&["**/*.txt"], &[], false);
+        let file_sets = get_all_files(TEST_DIR, &matcher, 0).unwrap();
+        let paths: HashSet<String> = file_sets.files().into_iter().map(|f| f.path).collect();
+
+        assert_eq!(paths.contains("root.txt"), true);
+        assert_eq!(paths.contains(&format!("sub{}nested.txt", std::path::MAIN_SEPARATOR)), true);
+        assert_eq!(file_sets.dirs().iter().any(|d| d.path == "sub"), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn exclude_skips_descent_into_excluded_directory() {
+        const TEST_DIR: &str = "test_get_all_files_exclude_descent";
+        fs::create_dir_all(Path::new(TEST_DIR).join("excluded")).unwrap();
+        fs::write(Path::new(TEST_DIR).join("kept.txt"), b"kept").unwrap();
+        fs::write(Path::new(TEST_DIR).join("excluded").join("hidden.txt"), b"hidden").unwrap();
+
+        let matcher = Matcher::new(&[], &["excluded"], false);
+        let file_sets = get_all_files(TEST_DIR, &matcher, 0).unwrap();
+        let paths: HashSet<String> = file_sets.files().into_iter().map(|f| f.path).collect();
+
+        assert_eq!(paths.contains("kept.txt"), true);
+        assert_eq!(paths.contains(&format!("excluded{}hidden.txt", std::path::MAIN_SEPARATOR)), false);
+        assert_eq!(file_sets.dirs().iter().any(|d| d.path == "excluded"), false);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn gitignore_skips_matched_paths_and_is_inherited_by_subdirectories() {
+        const TEST_DIR: &str = "test_get_all_files_gitignore";
+        fs::create_dir_all(Path::new(TEST_DIR).join("sub")).unwrap();
+        fs::write(Path::new(TEST_DIR).join(".gitignore"), b"ignored.txt\n").unwrap();
+        fs::write(Path::new(TEST_DIR).join("kept.txt"), b"kept").unwrap();
+        fs::write(Path::new(TEST_DIR).join("ignored.txt"), b"ignored").unwrap();
+        fs::write(Path::new(TEST_DIR).join("sub").join("ignored.txt"), b"ignored").unwrap();
+        fs::write(Path::new(TEST_DIR).join("sub").join("kept.txt"), b"kept").unwrap();
+
+        let matcher = Matcher::new(&[], &[], true);
+        let file_sets = get_all_files(TEST_DIR, &matcher, 0).unwrap();
+        let paths: HashSet<String> = file_sets.files().into_iter().map(|f| f.path).collect();
+
+        assert_eq!(paths.contains("kept.txt"), true);
+        assert_eq!(paths.contains("ignored.txt"), false);
+        assert_eq!(paths.contains(&format!("sub{}kept.txt", std::path::MAIN_SEPARATOR)), true);
+        assert_eq!(paths.contains(&format!("sub{}ignored.txt", std::path::MAIN_SEPARATOR)), false);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+/// Set operations used to determine what needs to be copied, compared, or
+/// deleted when syncing two `FileSets`
+pub trait Syncable<T> {
+    fn par_difference<'a>(&'a self, other: &'a HashSet<T>) -> HashSet<&'a T>;
+    fn par_intersection<'a>(&'a self, other: &'a HashSet<T>) -> HashSet<&'a T>;
+}
+
+impl<T> Syncable<T> for HashSet<T>
+where
+    T: Eq + Hash + Sync,
+{
+    fn par_difference<'a>(&'a self, other: &'a HashSet<T>) -> HashSet<&'a T> {
+        self.par_iter().filter(|item| !other.contains(item)).collect()
+    }
+
+    fn par_intersection<'a>(&'a self, other: &'a HashSet<T>) -> HashSet<&'a T> {
+        self.par_iter().filter(|item| other.contains(item)).collect()
+    }
+}
+
+/// Sorts `set` in descending order by path, so that the deepest entries
+/// (e.g. nested directories) come first
+///
+/// This is used to delete directories in the correct order: children
+/// before their parents
+pub fn sort_files<T: Ord>(set: HashSet<T>) -> Vec<T> {
+    let mut files: Vec<T> = set.into_iter().collect();
+    files.sort_by(|a, b| b.cmp(a));
+    files
+}
+
+/// Copies all of `files` from `src` to `dest`, in parallel
+///
+/// Every copy is attempted even if others fail; the returned `SyncSummary`
+/// records which paths succeeded and which failed, with their errors
+pub fn copy_files<T>(files: impl IntoParallelIterator<Item = T>, src: &str, dest: &str, flags: u32) -> SyncSummary
+where
+    T: Entry + Sync + Send,
+{
+    files
+        .into_par_iter()
+        .map(|file| {
+            let src_path = Path::new(src).join(file.path());
+            let dest_path = Path::new(dest).join(file.path());
+
+            match copy(&src_path, &dest_path, flags) {
+                Ok(()) => SyncSummary::success(file.path()),
+                Err(e) => {
+                    error!("Copy Error: {} -> {}: {}", src_path.display(), dest_path.display(), e);
+                    SyncSummary::failure(file.path(), e)
+                }
+            }
+        })
+        .reduce(SyncSummary::default, SyncSummary::merge)
+}
+
+/// Copies a single file, dir, or symlink from `src_path` to `dest_path`
+///
+/// When `Flag::Reflink` is set and both paths live on a CoW-capable
+/// filesystem (e.g. btrfs, XFS), the copy is made by sharing extents with
+/// the kernel's `FICLONE` ioctl instead of reading and rewriting the file's
+/// bytes. If cloning isn't supported here (see `fallback_eligible`) this
+/// falls back to `atomic_copy_file`, unless `Flag::ReflinkAlways` is also set, in
+/// which case the error is surfaced instead of silently falling back
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src_path` does not exist
+/// * `dest_path` cannot be created
+/// * `Flag::ReflinkAlways` is set and the filesystem does not support cloning
+fn copy(src_path: &Path, dest_path: &Path, flags: u32) -> Result<(), io::Error> {
+    let metadata = fs::symlink_metadata(src_path)?;
+
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(src_path)?;
+        let _ = fs::remove_file(dest_path);
+        symlink(&target, dest_path)?;
+        return Ok(());
+    } else if metadata.is_dir() {
+        fs::create_dir_all(dest_path)?;
+    } else if parse::contains_flag(flags, Flag::Reflink) {
+        reflink_copy(src_path, dest_path, parse::contains_flag(flags, Flag::ReflinkAlways))?;
+    } else {
+        atomic_copy_file(src_path, dest_path)?;
+    }
+
+    if parse::contains_flag(flags, Flag::Archive) {
+        apply_metadata(src_path, dest_path)?;
+    }
+
+    Ok(())
+}
+
+/// Copies `src_path` to `dest_path` by cloning extents with the kernel's
+/// `FICLONE` ioctl, falling back to `atomic_copy_file` unless `always` is set
+///
+/// The clone is written into a temporary file, fsynced, then renamed into
+/// place, the same crash-safe write-then-rename `atomic_copy_file` uses, so
+/// `dest_path` is never observed half-cloned
+#[cfg(target_os = "linux")]
+fn reflink_copy(src_path: &Path, dest_path: &Path, always: bool) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    const FICLONE: u64 = 0x40049409;
+
+    let tmp_path = tmp_path_for(dest_path);
+    let src_file = fs::File::open(src_path)?;
+    let tmp_file = create_in_parent(&tmp_path)?;
+
+    let result = unsafe { libc_ioctl(tmp_file.as_raw_fd(), FICLONE, src_file.as_raw_fd()) };
+
+    if result == 0 {
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        fs::rename(&tmp_path, dest_path)?;
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    let _ = fs::remove_file(&tmp_path);
+
+    if always || !fallback_eligible(&err) {
+        return Err(err);
+    }
+
+    atomic_copy_file(src_path, dest_path)
+}
+
+/// Whether `err` indicates the filesystem simply doesn't support `FICLONE`,
+/// in which case `reflink_copy` should fall back to a full copy instead of
+/// surfacing the error
+///
+/// `ENOTTY` is what most filesystems return for an ioctl they don't
+/// implement at all (the common case outside btrfs/XFS/overlay); `EXDEV`
+/// covers cross-filesystem clones, and `EOPNOTSUPP`/`ENOSYS` cover
+/// filesystems or kernels that recognize the ioctl but can't honor it
+#[cfg(target_os = "linux")]
+fn fallback_eligible(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EXDEV) | Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::ENOSYS)
+    )
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn libc_ioctl(fd: std::os::unix::io::RawFd, request: u64, src_fd: std::os::unix::io::RawFd) -> i32 {
+    libc::ioctl(fd, request, src_fd)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink_copy(src_path: &Path, dest_path: &Path, always: bool) -> Result<(), io::Error> {
+    if always {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "reflink copies are only supported on Linux",
+        ));
+    }
+
+    atomic_copy_file(src_path, dest_path)
+}
+
+/// Copies `src_path` to `dest_path` atomically: the contents are written to
+/// a uniquely-named temporary file in `dest_path`'s own directory (so the
+/// final rename stays on one filesystem), fsynced, then renamed over
+/// `dest_path` in a single syscall
+///
+/// This guarantees `dest_path` is never observed partially written, even if
+/// the process is killed mid-copy
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src_path` does not exist
+/// * `dest_path`'s parent directory cannot be created
+fn atomic_copy_file(src_path: &Path, dest_path: &Path) -> Result<(), io::Error> {
+    let tmp_path = tmp_path_for(dest_path);
+
+    let mut src_file = fs::File::open(src_path)?;
+    let mut tmp_file = create_in_parent(&tmp_path)?;
+    io::copy(&mut src_file, &mut tmp_file)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+
+    fs::rename(&tmp_path, dest_path)?;
+
+    Ok(())
+}
+
+/// Creates the file at `path`, recreating its parent directory first if it
+/// is missing
+fn create_in_parent(path: &Path) -> Result<fs::File, io::Error> {
+    match fs::File::create(path) {
+        Ok(file) => Ok(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::File::create(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test_atomic_copy_file {
+    use super::*;
+
+    #[test]
+    fn copies_contents_and_cleans_up_tmp_file() {
+        const TEST_DIR: &str = "test_atomic_copy_file_basic";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        let src_path = Path::new(TEST_DIR).join("src.txt");
+        let dest_path = Path::new(TEST_DIR).join("dest.txt");
+        fs::write(&src_path, b"hello atomic world").unwrap();
+
+        atomic_copy_file(&src_path, &dest_path).unwrap();
+
+        assert_eq!(fs::read(&dest_path).unwrap(), b"hello atomic world");
+        assert_eq!(fs::read_dir(TEST_DIR).unwrap().count(), 2);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn recreates_missing_parent_directory() {
+        const TEST_DIR: &str = "test_atomic_copy_file_missing_parent";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        let src_path = Path::new(TEST_DIR).join("src.txt");
+        fs::write(&src_path, b"content").unwrap();
+
+        let dest_dir = Path::new(TEST_DIR).join("nested").join("deeper");
+        let dest_path = dest_dir.join("dest.txt");
+        assert_eq!(dest_dir.exists(), false);
+
+        atomic_copy_file(&src_path, &dest_path).unwrap();
+
+        assert_eq!(fs::read(&dest_path).unwrap(), b"content");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn symlink(target: &Path, link: &Path) -> Result<(), io::Error> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(target_family = "windows")]
+fn symlink(target: &Path, link: &Path) -> Result<(), io::Error> {
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Deletes all of `files` from `dest`, in parallel
+///
+/// Every delete is attempted even if others fail; the returned
+/// `SyncSummary` records which paths succeeded and which failed, with
+/// their errors
+pub fn delete_files<T>(files: impl IntoParallelIterator<Item = T>, dest: &str) -> SyncSummary
+where
+    T: Entry + Sync + Send,
+{
+    files
+        .into_par_iter()
+        .map(|file| {
+            let dest_path = Path::new(dest).join(file.path());
+
+            let result = if dest_path.is_dir() && !dest_path.is_symlink_safe() {
+                fs::remove_dir_all(&dest_path)
+            } else {
+                fs::remove_file(&dest_path)
+            };
+
+            match result {
+                Ok(()) => SyncSummary::success(file.path()),
+                Err(e) => {
+                    error!("Delete Error: {}: {}", dest_path.display(), e);
+                    SyncSummary::failure(file.path(), e)
+                }
+            }
+        })
+        .reduce(SyncSummary::default, SyncSummary::merge)
+}
+
+/// Deletes all of `files` from `dest`, one at a time, in the order given
+///
+/// Used for deleting directories, where children must be removed before
+/// their parents
+pub fn delete_files_sequential<T>(files: Vec<T>, dest: &str) -> SyncSummary
+where
+    T: Entry,
+{
+    let mut summary = SyncSummary::default();
+
+    for file in files {
+        let dest_path = Path::new(dest).join(file.path());
+
+        summary = summary.merge(match fs::remove_dir(&dest_path) {
+            Ok(()) => SyncSummary::success(file.path()),
+            Err(e) => {
+                error!("Delete Error: {}: {}", dest_path.display(), e);
+                SyncSummary::failure(file.path(), e)
+            }
+        });
+    }
+
+    summary
+}
+
+trait IsSymlinkSafe {
+    fn is_symlink_safe(&self) -> bool;
+}
+
+impl IsSymlinkSafe for Path {
+    fn is_symlink_safe(&self) -> bool {
+        fs::symlink_metadata(self)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false)
+    }
+}
+
+/// Re-applies `src_path`'s permissions, timestamps, and (on Unix) ownership
+/// and extended attributes onto `dest_path`
+///
+/// Used for `Flag::Archive` so that a copy is a true archival mirror, not
+/// just a content-identical one
+fn apply_metadata(src_path: &Path, dest_path: &Path) -> Result<(), io::Error> {
+    let metadata = fs::symlink_metadata(src_path)?;
+
+    fs::set_permissions(dest_path, metadata.permissions())?;
+
+    let atime = filetime::FileTime::from_last_access_time(&metadata);
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+    filetime::set_file_times(dest_path, atime, mtime)?;
+
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::{chown, MetadataExt};
+
+        // Best-effort: changing ownership to an arbitrary uid/gid requires
+        // CAP_CHOWN/root, so a non-root user mirroring a tree with
+        // `--archive` would otherwise have every copy reported as a
+        // failure purely because of this step, even though the content,
+        // permissions, and timestamps were all applied successfully
+        if let Err(e) = chown(dest_path, Some(metadata.uid()), Some(metadata.gid())) {
+            error!("Chown Error: {}: {}", dest_path.display(), e);
+        }
+
+        for (name, value) in read_xattrs(src_path) {
+            let _ = xattr::set(dest_path, &name, &value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Compares each file in `files` between `src` and `dest`, copying over
+/// any that differ
+///
+/// When `Flag::Delta` is set, files that exist on both sides are updated
+/// with a block-level delta copy instead of a full re-copy; otherwise the
+/// whole file is re-copied with `atomic_copy_file`
+///
+/// Since `File`'s equality (and hence `HashSet` membership) is path-only, a
+/// path that differs only in its `Flag::Archive` metadata (permissions,
+/// timestamps, xattrs) also lands here rather than in the delete+copy
+/// paths. In that case the content is left untouched and only the metadata
+/// is re-applied, avoiding a full rewrite for a permission or mtime change
+pub fn compare_and_copy_files<'a>(
+    files: impl IntoParallelIterator<Item = &'a File>,
+    src: &str,
+    dest: &str,
+    flags: u32,
+) -> SyncSummary {
+    let delta = parse::contains_flag(flags, Flag::Delta);
+    let archive = parse::contains_flag(flags, Flag::Archive);
+
+    files
+        .into_par_iter()
+        .map(|file| {
+            let src_path = Path::new(src).join(file.path());
+            let dest_path = Path::new(dest).join(file.path());
+
+            let content_differs = match fs::metadata(&dest_path) {
+                Ok(dest_metadata) if dest_metadata.len() == file.size => {
+                    match (hash_file(&src_path), hash_file(&dest_path)) {
+                        (Ok(src_hash), Ok(dest_hash)) => src_hash != dest_hash,
+                        _ => true,
+                    }
+                }
+                _ => true,
+            };
+
+            if content_differs {
+                let result = if delta {
+                    delta_copy(&src_path, &dest_path)
+                } else {
+                    atomic_copy_file(&src_path, &dest_path)
+                };
+
+                if let Err(e) = result {
+                    error!("Copy Error: {} -> {}: {}", src_path.display(), dest_path.display(), e);
+                    return SyncSummary::failure(file.path(), e);
+                }
+            } else if archive && file.metadata != FileMetadata::capture(&dest_path) {
+                if let Err(e) = apply_metadata(&src_path, &dest_path) {
+                    error!("Metadata Error: {} -> {}: {}", src_path.display(), dest_path.display(), e);
+                    return SyncSummary::failure(file.path(), e);
+                }
+                return SyncSummary::success(file.path());
+            } else {
+                return SyncSummary::success(file.path());
+            }
+
+            if archive {
+                if let Err(e) = apply_metadata(&src_path, &dest_path) {
+                    error!("Metadata Error: {} -> {}: {}", src_path.display(), dest_path.display(), e);
+                    return SyncSummary::failure(file.path(), e);
+                }
+            }
+
+            SyncSummary::success(file.path())
+        })
+        .reduce(SyncSummary::default, SyncSummary::merge)
+}
+
+#[cfg(test)]
+#[cfg(target_family = "unix")]
+mod test_compare_and_copy_files {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn metadata_only_change_updates_permissions_without_rewriting_content() {
+        const TEST_DIR: &str = "test_compare_and_copy_files_metadata_only";
+        let src_dir = Path::new(TEST_DIR).join("src");
+        let dest_dir = Path::new(TEST_DIR).join("dest");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let src_path = src_dir.join("file.txt");
+        let dest_path = dest_dir.join("file.txt");
+        fs::write(&src_path, b"unchanged content").unwrap();
+        fs::write(&dest_path, b"unchanged content").unwrap();
+
+        fs::set_permissions(&src_path, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(&dest_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let dest_inode_before = fs::metadata(&dest_path).unwrap().ino();
+
+        let file = File::new("file.txt", 17, FileMetadata::capture(&src_path));
+        let files = vec![file];
+        let flags = parse::Flag::Archive as u32;
+
+        let summary = compare_and_copy_files(
+            files.par_iter(),
+            src_dir.to_str().unwrap(),
+            dest_dir.to_str().unwrap(),
+            flags,
+        );
+
+        assert_eq!(summary.is_success(), true);
+        assert_eq!(fs::read(&dest_path).unwrap(), b"unchanged content");
+        assert_eq!(fs::metadata(&dest_path).unwrap().ino(), dest_inode_before);
+        assert_eq!(fs::metadata(&dest_path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+/// Computes a Blake2s digest of the file at `path`
+pub(crate) fn hash_file(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let contents = fs::read(path)?;
+    let mut hasher = Blake2s::new();
+    hasher.update(&contents);
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Returns `(size, hash, mtime)` for the file at `path`, or `None` if it
+/// does not exist
+///
+/// Used by `core::sync_bidirectional` to fingerprint a file for comparison
+/// against its archived snapshot
+pub(crate) fn fingerprint(path: &Path) -> Option<(u64, Vec<u8>, i64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let hash = hash_file(path).ok()?;
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata).seconds();
+
+    Some((metadata.len(), hash, mtime))
+}
+
+/// A single strong hash fingerprint for one block of `dest`, bucketed by
+/// weak checksum in the `HashMap` returned from `index_blocks`
+struct BlockSignature {
+    strong: Vec<u8>,
+    index: usize,
+}
+
+/// Splits `dest` into fixed-size blocks and indexes each one by its weak
+/// rolling checksum, so the source scan below can find candidate matches
+/// in O(1)
+fn index_blocks(dest: &[u8]) -> HashMap<u32, Vec<BlockSignature>> {
+    let mut index: HashMap<u32, Vec<BlockSignature>> = HashMap::new();
+
+    for (i, chunk) in dest.chunks(DELTA_BLOCK_SIZE).enumerate() {
+        let weak = weak_checksum(chunk);
+        let mut hasher = Blake2s::new();
+        hasher.update(chunk);
+        let strong = hasher.finalize().to_vec();
+
+        index
+            .entry(weak)
+            .or_default()
+            .push(BlockSignature { strong, index: i });
+    }
+
+    index
+}
+
+/// Computes the Adler-style weak rolling checksum of `block`
+fn weak_checksum(block: &[u8]) -> u32 {
+    let mut s1: u32 = 0;
+    let mut s2: u32 = 0;
+    let len = block.len();
+
+    for (i, &byte) in block.iter().enumerate() {
+        s1 = (s1 + byte as u32) % ADLER_MOD;
+        s2 = (s2 + (len - i) as u32 * byte as u32) % ADLER_MOD;
+    }
+
+    s1 | (s2 << 16)
+}
+
+/// An instruction for reconstructing the new destination file: either a
+/// verbatim byte copied from the source, or a whole block reused from the
+/// existing destination file
+enum Instruction {
+    Literal(u8),
+    CopyBlock(usize),
+}
+
+/// Scans `src` against the blocks indexed from `dest`, emitting a stream of
+/// instructions describing how to reconstruct `src` from a minimal set of
+/// new bytes plus reused blocks from `dest`
+fn compute_delta(src: &[u8], dest: &[u8]) -> Vec<Instruction> {
+    let block_index = index_blocks(dest);
+    let mut instructions = Vec::new();
+
+    if src.is_empty() {
+        return instructions;
+    }
+
+    let mut pos = 0;
+    let mut window_end = DELTA_BLOCK_SIZE.min(src.len());
+    let mut weak = weak_checksum(&src[pos..window_end]);
+
+    while pos < src.len() {
+        let window = &src[pos..window_end];
+        let mut matched = false;
+
+        if window.len() == DELTA_BLOCK_SIZE {
+            if let Some(candidates) = block_index.get(&weak) {
+                let mut hasher = Blake2s::new();
+                hasher.update(window);
+                let strong = hasher.finalize().to_vec();
+
+                if let Some(block) = candidates.iter().find(|c| c.strong == strong) {
+                    instructions.push(Instruction::CopyBlock(block.index));
+                    matched = true;
+                    pos = window_end;
+                    window_end = (pos + DELTA_BLOCK_SIZE).min(src.len());
+                    if pos < src.len() {
+                        weak = weak_checksum(&src[pos..window_end]);
+                    }
+                }
+            }
+        }
+
+        if !matched {
+            instructions.push(Instruction::Literal(src[pos]));
+
+            let outgoing = src[pos];
+            pos += 1;
+            window_end = (window_end + 1).min(src.len());
+            if window_end > pos && window_end - pos == DELTA_BLOCK_SIZE.min(src.len() - pos) && pos < src.len() {
+                let incoming = src[window_end - 1];
+                weak = roll_checksum(weak, outgoing, incoming, window.len());
+            } else if pos < src.len() {
+                weak = weak_checksum(&src[pos..window_end]);
+            }
+        }
+    }
+
+    instructions
+}
+
+/// Updates a rolling checksum in O(1) by subtracting the outgoing byte and
+/// adding the incoming byte
+fn roll_checksum(weak: u32, outgoing: u8, incoming: u8, len: usize) -> u32 {
+    let s1 = weak & 0xffff;
+    let s2 = (weak >> 16) & 0xffff;
+
+    let s1 = (s1 + ADLER_MOD - outgoing as u32 % ADLER_MOD + incoming as u32) % ADLER_MOD;
+    let s2 = (s2 + ADLER_MOD - (len as u32 * outgoing as u32) % ADLER_MOD + s1) % ADLER_MOD;
+
+    s1 | (s2 << 16)
+}
+
+/// Reconstructs `src_path` into `dest_path` using a block-level delta: only
+/// the literal bytes that changed are transmitted, the rest are reused
+/// directly from the existing `dest_path` contents
+///
+/// The reconstructed file is written to a temporary file and renamed into
+/// place, so `dest_path` always byte-for-byte equals `src_path` once this
+/// returns successfully
+fn delta_copy(src_path: &Path, dest_path: &Path) -> Result<(), io::Error> {
+    let src_contents = fs::read(src_path)?;
+    let dest_contents = fs::read(dest_path).unwrap_or_default();
+
+    let instructions = compute_delta(&src_contents, &dest_contents);
+
+    let mut reconstructed = Vec::with_capacity(src_contents.len());
+    for instruction in instructions {
+        match instruction {
+            Instruction::Literal(byte) => reconstructed.push(byte),
+            Instruction::CopyBlock(index) => {
+                let start = index * DELTA_BLOCK_SIZE;
+                let end = (start + DELTA_BLOCK_SIZE).min(dest_contents.len());
+                reconstructed.extend_from_slice(&dest_contents[start..end]);
+            }
+        }
+    }
+
+    let tmp_path = tmp_path_for(dest_path);
+    let mut tmp_file = create_in_parent(&tmp_path)?;
+    tmp_file.write_all(&reconstructed)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+    fs::rename(&tmp_path, dest_path)?;
+
+    Ok(())
+}
+
+/// Builds a unique temporary-file path alongside `path`, in the same
+/// directory, so a subsequent rename stays on one filesystem
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "tmp".to_string());
+    let pid = std::process::id();
+
+    path.with_file_name(format!(".{}.lumins-tmp-{}", file_name, pid))
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod test_reflink_fallback {
+    use super::*;
+
+    #[test]
+    fn falls_back_for_unimplemented_ioctl() {
+        let err = io::Error::from_raw_os_error(libc::ENOTTY);
+        assert_eq!(fallback_eligible(&err), true);
+    }
+
+    #[test]
+    fn falls_back_across_filesystems() {
+        let err = io::Error::from_raw_os_error(libc::EXDEV);
+        assert_eq!(fallback_eligible(&err), true);
+    }
+
+    #[test]
+    fn falls_back_when_unsupported() {
+        let err = io::Error::from_raw_os_error(libc::EOPNOTSUPP);
+        assert_eq!(fallback_eligible(&err), true);
+        let err = io::Error::from_raw_os_error(libc::ENOSYS);
+        assert_eq!(fallback_eligible(&err), true);
+    }
+
+    #[test]
+    fn does_not_fall_back_for_other_errors() {
+        let err = io::Error::from_raw_os_error(libc::EACCES);
+        assert_eq!(fallback_eligible(&err), false);
+    }
+}
+
+#[cfg(test)]
+mod test_weak_checksum {
+    use super::*;
+
+    #[test]
+    fn matches_for_identical_blocks() {
+        let a = b"the quick brown fox";
+        let b = b"the quick brown fox";
+        assert_eq!(weak_checksum(a), weak_checksum(b));
+    }
+
+    #[test]
+    fn differs_for_different_blocks() {
+        let a = b"the quick brown fox";
+        let b = b"the slow brown fox!";
+        assert_ne!(weak_checksum(a), weak_checksum(b));
+    }
+}
+
+#[cfg(test)]
+mod test_compute_delta {
+    use super::*;
+
+    #[test]
+    fn reconstructs_identical_file_from_blocks() {
+        let dest = vec![7u8; DELTA_BLOCK_SIZE * 3];
+        let src = dest.clone();
+
+        let instructions = compute_delta(&src, &dest);
+        let mut reconstructed = Vec::new();
+        for instruction in instructions {
+            match instruction {
+                Instruction::Literal(b) => reconstructed.push(b),
+                Instruction::CopyBlock(i) => {
+                    let start = i * DELTA_BLOCK_SIZE;
+                    let end = (start + DELTA_BLOCK_SIZE).min(dest.len());
+                    reconstructed.extend_from_slice(&dest[start..end]);
+                }
+            }
+        }
+
+        assert_eq!(reconstructed, src);
+    }
+
+    #[test]
+    fn reconstructs_appended_file() {
+        let dest = vec![1u8; DELTA_BLOCK_SIZE];
+        let mut src = dest.clone();
+        src.extend_from_slice(b"new tail bytes");
+
+        let instructions = compute_delta(&src, &dest);
+        let mut reconstructed = Vec::new();
+        for instruction in instructions {
+            match instruction {
+                Instruction::Literal(b) => reconstructed.push(b),
+                Instruction::CopyBlock(i) => {
+                    let start = i * DELTA_BLOCK_SIZE;
+                    let end = (start + DELTA_BLOCK_SIZE).min(dest.len());
+                    reconstructed.extend_from_slice(&dest[start..end]);
+                }
+            }
+        }
+
+        assert_eq!(reconstructed, src);
+    }
+}
@@ -1,6 +1,7 @@
 use std::fs;
 
 use clap::ArgMatches;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 
 #[repr(u32)]
 pub enum Flag {
@@ -8,12 +9,94 @@ pub enum Flag {
     NoDelete = 1 << 1,
     Secure = 1 << 2,
     Verbose = 1 << 3,
+    Delta = 1 << 4,
+    Reflink = 1 << 5,
+    ReflinkAlways = 1 << 6,
+    Archive = 1 << 7,
 }
 
 pub struct ParseResult<'a> {
     pub src: &'a str,
     pub dest: &'a str,
     pub flags: u32,
+    pub matcher: Matcher,
+}
+
+/// Decides whether a path (relative to the root of the tree being synced)
+/// should be included in a sync, based on `--include`/`--exclude` globs and
+/// an optional `--gitignore` switch
+pub struct Matcher {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    pub gitignore: bool,
+}
+
+impl Default for Matcher {
+    /// A matcher that includes everything: no `--include`/`--exclude` globs
+    /// and `--gitignore` disabled
+    fn default() -> Matcher {
+        Matcher::new(&[], &[], false)
+    }
+}
+
+impl Matcher {
+    pub(crate) fn new(includes: &[&str], excludes: &[&str], gitignore: bool) -> Matcher {
+        let compile = |patterns: &[&str]| -> Option<GlobSet> {
+            if patterns.is_empty() {
+                return None;
+            }
+
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                if let Ok(glob) = Glob::new(pattern) {
+                    builder.add(glob);
+                } else {
+                    eprintln!("Pattern Error: invalid glob '{}'", pattern);
+                }
+            }
+            builder.build().ok()
+        };
+
+        Matcher {
+            include: compile(includes),
+            exclude: compile(excludes),
+            gitignore,
+        }
+    }
+
+    /// Returns whether `relative_path` should be part of the sync
+    ///
+    /// A path excluded by `--exclude` is always dropped; otherwise, if any
+    /// `--include` globs were given, the path must match one of them
+    ///
+    /// This is meant for files and symlinks, which are leaves in the tree.
+    /// For directories, use `is_excluded` instead: a directory's own path
+    /// essentially never matches a file-shaped `--include` glob (e.g. `sub`
+    /// doesn't match `*.txt`), so gating traversal on `is_included` would
+    /// stop the walk from ever reaching the included files underneath it
+    pub fn is_included(&self, relative_path: &str) -> bool {
+        if self.is_excluded(relative_path) {
+            return false;
+        }
+
+        match &self.include {
+            Some(include) => include.is_match(relative_path),
+            None => true,
+        }
+    }
+
+    /// Returns whether `relative_path` is rejected by `--exclude`, ignoring
+    /// `--include`
+    ///
+    /// Used to decide whether to descend into a directory at all: a
+    /// directory is always traversed unless explicitly excluded, regardless
+    /// of whether its own path happens to match an `--include` glob
+    pub fn is_excluded(&self, relative_path: &str) -> bool {
+        match &self.exclude {
+            Some(exclude) => exclude.is_match(relative_path),
+            None => false,
+        }
+    }
 }
 
 /// Parses command line arguments for source and destination folders and
@@ -64,14 +147,74 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
     if args.is_present("secure") {
         flags |= Flag::Secure as u32;
     }
+    if args.is_present("delta") {
+        flags |= Flag::Delta as u32;
+    }
+    if args.is_present("reflink") {
+        flags |= Flag::Reflink as u32;
+    }
+    if args.is_present("reflink_always") {
+        flags |= Flag::Reflink as u32;
+        flags |= Flag::ReflinkAlways as u32;
+    }
+    if args.is_present("archive") {
+        flags |= Flag::Archive as u32;
+    }
+
+    let includes: Vec<&str> = args.values_of("include").map_or(Vec::new(), |v| v.collect());
+    let excludes: Vec<&str> = args.values_of("exclude").map_or(Vec::new(), |v| v.collect());
+    let gitignore = args.is_present("gitignore");
+    let matcher = Matcher::new(&includes, &excludes, gitignore);
 
-    Ok(ParseResult { src, dest, flags })
+    Ok(ParseResult {
+        src,
+        dest,
+        flags,
+        matcher,
+    })
 }
 
 pub fn contains_flag(bitfield: u32, flag: Flag) -> bool {
     (bitfield >> (((flag as u32) as f32).log2() as u32) & 1) == 1
 }
 
+#[cfg(test)]
+mod test_matcher {
+    use super::*;
+
+    #[test]
+    fn includes_everything_by_default() {
+        let matcher = Matcher::default();
+        assert_eq!(matcher.is_included("anything.txt"), true);
+        assert_eq!(matcher.is_excluded("anything.txt"), false);
+    }
+
+    #[test]
+    fn exclude_rejects_matching_files() {
+        let matcher = Matcher::new(&[], &["*.log"], false);
+        assert_eq!(matcher.is_included("debug.log"), false);
+        assert_eq!(matcher.is_excluded("debug.log"), true);
+        assert_eq!(matcher.is_included("debug.txt"), true);
+    }
+
+    #[test]
+    fn include_rejects_files_that_miss_every_glob() {
+        let matcher = Matcher::new(&["*.txt"], &[], false);
+        assert_eq!(matcher.is_included("notes.txt"), true);
+        assert_eq!(matcher.is_included("notes.md"), false);
+    }
+
+    #[test]
+    fn is_excluded_ignores_include_globs() {
+        // A directory's own name (e.g. "sub") essentially never matches a
+        // file-shaped include glob like "*.txt" -- is_excluded must not
+        // reject it on that basis, or `walk` would never descend into it
+        let matcher = Matcher::new(&["*.txt"], &[], false);
+        assert_eq!(matcher.is_excluded("sub"), false);
+        assert_eq!(matcher.is_included("sub"), false);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
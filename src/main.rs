@@ -0,0 +1,144 @@
+use clap::{App, Arg};
+
+mod lumins;
+
+use lumins::core::{self, Prefer};
+use lumins::parse;
+
+/// Process exit codes distinguishing a clean run from the ways it can fail
+mod exit_code {
+    /// Every operation succeeded
+    pub const SUCCESS: i32 = 0;
+    /// `src`/`dest` could not be parsed or read, or the run failed as a
+    /// whole (e.g. the `--bidirectional` archive could not be written)
+    pub const INVALID_ARGS: i32 = 1;
+    /// Arguments were valid, but one or more files failed to sync
+    pub const PARTIAL_FAILURE: i32 = 2;
+}
+
+fn main() {
+    let args = App::new("lumins")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A fast, parallel file/directory synchronization utility")
+        .arg(Arg::with_name("SOURCE").required(true).index(1))
+        .arg(Arg::with_name("DESTINATION").required(true).index(2))
+        .arg(
+            Arg::with_name("copy")
+                .short("c")
+                .long("copy")
+                .help("Copies SOURCE into DESTINATION without deleting extraneous files"),
+        )
+        .arg(
+            Arg::with_name("nodelete")
+                .short("n")
+                .long("no-delete")
+                .help("Does not delete files in DESTINATION that are not in SOURCE"),
+        )
+        .arg(Arg::with_name("secure").long("secure").help("Uses a secure hash to compare files"))
+        .arg(Arg::with_name("verbose").short("v").long("verbose").help("Logs every operation"))
+        .arg(
+            Arg::with_name("delta")
+                .long("delta")
+                .help("Updates changed files with a block-level delta copy instead of a full re-copy"),
+        )
+        .arg(
+            Arg::with_name("reflink")
+                .long("reflink")
+                .help("Uses copy-on-write reflinks where supported, falling back to a full copy"),
+        )
+        .arg(
+            Arg::with_name("reflink_always")
+                .long("reflink-always")
+                .conflicts_with("reflink")
+                .help("Uses copy-on-write reflinks, erroring out instead of falling back"),
+        )
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .help("Preserves permissions, timestamps, ownership, and extended attributes"),
+        )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only syncs paths matching this glob (repeatable)"),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Skips paths matching this glob (repeatable)"),
+        )
+        .arg(
+            Arg::with_name("gitignore")
+                .long("gitignore")
+                .help("Skips paths ignored by any .gitignore encountered while walking"),
+        )
+        .arg(
+            Arg::with_name("bidirectional")
+                .short("b")
+                .long("bidirectional")
+                .takes_value(true)
+                .value_name("ARCHIVE")
+                .help("Two-way syncs SOURCE and DESTINATION, tracking state in ARCHIVE"),
+        )
+        .arg(
+            Arg::with_name("prefer")
+                .long("prefer")
+                .takes_value(true)
+                .possible_values(&["src", "dest", "newer"])
+                .requires("bidirectional")
+                .help("How to resolve a path changed on both sides during --bidirectional"),
+        )
+        .get_matches();
+
+    let parsed = match parse::parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(()) => std::process::exit(exit_code::INVALID_ARGS),
+    };
+
+    let result = if let Some(archive_path) = args.value_of("bidirectional") {
+        let prefer = match args.value_of("prefer") {
+            Some("src") => Some(Prefer::Src),
+            Some("dest") => Some(Prefer::Dest),
+            Some("newer") => Some(Prefer::Newer),
+            _ => None,
+        };
+
+        core::sync_bidirectional(
+            parsed.src,
+            parsed.dest,
+            archive_path,
+            parsed.flags,
+            &parsed.matcher,
+            &prefer,
+        )
+    } else if parse::contains_flag(parsed.flags, parse::Flag::Copy) {
+        core::copy(parsed.src, parsed.dest, parsed.flags, &parsed.matcher)
+    } else {
+        core::synchronize(parsed.src, parsed.dest, parsed.flags, &parsed.matcher)
+    };
+
+    match result {
+        Ok(summary) => {
+            for (path, error) in &summary.failed {
+                eprintln!("Failed: {}: {}", path, error);
+            }
+
+            if summary.is_success() {
+                std::process::exit(exit_code::SUCCESS);
+            } else {
+                std::process::exit(exit_code::PARTIAL_FAILURE);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(exit_code::INVALID_ARGS);
+        }
+    }
+}